@@ -4,6 +4,8 @@ use ic_cdk::export::candid;
 use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::collections::HashSet;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 
@@ -18,6 +20,7 @@ pub struct Patient {
     pub location: String,
     pub preferences: Vec<String>,
     pub created_at: i64,
+    pub version: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -32,6 +35,7 @@ pub struct ClinicalTrial {
     pub duration: String,
     pub status: String,
     pub created_at: i64,
+    pub version: u64,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -42,6 +46,24 @@ pub struct Match {
     pub match_score: f64,
     pub status: String,
     pub created_at: i64,
+    pub version: u64,
+}
+
+// Returned by compare-and-swap updates when the caller's `expected_version`
+// no longer matches the stored record, so a concurrent writer doesn't get
+// silently clobbered.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum UpdateError {
+    NotFound,
+    Conflict { current_version: u64 },
+    InvalidTransition { current_status: String },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum Role {
+    Admin,
+    Researcher,
+    Patient,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -51,6 +73,56 @@ pub struct FetchAgent {
     pub address: String,
     pub capabilities: Vec<String>,
     pub status: String,
+    pub last_heartbeat: i64,
+}
+
+// Fixed-size bucket storage: stable memory is carved into uniform cells, each
+// holding one chunk of a record behind a small header (occupied flag, record
+// kind, a UID tag, this chunk's payload length, and a continuation flag) so a
+// cell can be located, freed, and reused without rewriting neighboring cells.
+// A record whose Candid encoding doesn't fit in one cell's payload spills
+// into consecutive cells (`continues = 1` on every cell but the last), so no
+// record size permanently blocks an upgrade the way a single hard-capped cell
+// would. The store header (magic + cell count written by `pre_upgrade`) lives
+// in a reserved prefix of `STORE_HEADER_RESERVED_BYTES` ahead of cell 0.
+const CELL_SIZE_BYTES: u64 = 512;
+const CELL_HEADER_BYTES: u64 = 15; // 1 occupied + 1 kind + 8 uid + 4 chunk len + 1 continues
+const CELL_PAYLOAD_BYTES: usize = (CELL_SIZE_BYTES - CELL_HEADER_BYTES) as usize;
+const STABLE_MEMORY_BUDGET_BYTES: u64 = 32 * 1024 * 1024;
+const MAX_CELLS: u64 = STABLE_MEMORY_BUDGET_BYTES / CELL_SIZE_BYTES;
+const WASM_PAGE_SIZE_BYTES: u64 = 65_536;
+const STORE_HEADER_RESERVED_BYTES: u64 = 64;
+const STORE_HEADER_MAGIC: &[u8; 4] = b"GGTS";
+
+const CELL_KIND_PATIENT: u8 = 0;
+const CELL_KIND_TRIAL: u8 = 1;
+const CELL_KIND_MATCH: u8 = 2;
+const CELL_KIND_AGENT: u8 = 3;
+const CELL_KIND_PROVENANCE: u8 = 4;
+const CELL_KIND_PRINCIPAL: u8 = 5;
+const CELL_KIND_MATCH_THRESHOLD: u8 = 6;
+const CELL_KIND_HEARTBEAT_WINDOW: u8 = 7;
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct StorageStats {
+    pub cell_count: u64,
+    pub capacity: u64,
+    pub bytes_used: u64,
+}
+
+// Append-only audit trail. Each event's `hash` commits to its own fields and
+// to the previous event's `hash`, so replaying the chain and recomputing
+// hashes detects any retroactive edit or deletion.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ProvenanceEvent {
+    pub id: String,
+    pub actor_principal: String,
+    pub action: String,
+    pub entity_type: String,
+    pub entity_id: String,
+    pub timestamp: i64,
+    pub prev_hash: String,
+    pub hash: String,
 }
 
 // State storage
@@ -58,6 +130,31 @@ static mut PATIENTS: Option<HashMap<String, Patient>> = None;
 static mut TRIALS: Option<HashMap<String, ClinicalTrial>> = None;
 static mut MATCHES: Option<HashMap<String, Match>> = None;
 static mut FETCH_AGENTS: Option<HashMap<String, FetchAgent>> = None;
+static mut PROVENANCE: Option<Vec<ProvenanceEvent>> = None;
+static mut PRINCIPALS: Option<HashMap<String, Role>> = None;
+static mut MATCH_SCORE_THRESHOLD: f64 = 0.5;
+
+// Relative weight of each scoring term in `compute_match_score`; they sum to 1.0.
+const CONDITION_OVERLAP_WEIGHT: f64 = 0.5;
+const LOCATION_WEIGHT: f64 = 0.3;
+const PREFERENCE_WEIGHT: f64 = 0.2;
+
+// `Match.status` state machine: a match starts `proposed` and can only move
+// to `accepted` or `rejected` from there — it never reverts or skips states.
+const MATCH_STATUS_PROPOSED: &str = "proposed";
+const MATCH_STATUS_ACCEPTED: &str = "accepted";
+const MATCH_STATUS_REJECTED: &str = "rejected";
+
+fn is_valid_match_transition(current_status: &str, next_status: &str) -> bool {
+    current_status == MATCH_STATUS_PROPOSED
+        && (next_status == MATCH_STATUS_ACCEPTED || next_status == MATCH_STATUS_REJECTED)
+}
+
+// Staleness windows past which an agent's last heartbeat downgrades its
+// reported status; recomputed on read rather than via a background timer.
+// Admin-configurable via `set_heartbeat_staleness_window`.
+static mut HEARTBEAT_DEGRADED_AFTER_SECONDS: i64 = 60;
+static mut HEARTBEAT_OFFLINE_AFTER_SECONDS: i64 = 300;
 
 // Initialize canister
 #[init]
@@ -67,7 +164,13 @@ fn init() {
         TRIALS = Some(HashMap::new());
         MATCHES = Some(HashMap::new());
         FETCH_AGENTS = Some(HashMap::new());
-        
+        PROVENANCE = Some(Vec::new());
+        PRINCIPALS = Some(HashMap::new());
+        PRINCIPALS
+            .as_mut()
+            .unwrap()
+            .insert(caller().to_string(), Role::Admin);
+
         // Register default Fetch.ai agents
         let default_agents = vec![
             FetchAgent {
@@ -76,6 +179,7 @@ fn init() {
                 address: "fetch1h6u0j6u0j6u0j6u0j6u0j6u0j6u0j6u0".to_string(),
                 capabilities: vec!["patient_analysis".to_string(), "condition_matching".to_string()],
                 status: "active".to_string(),
+                last_heartbeat: Utc::now().timestamp(),
             },
             FetchAgent {
                 id: "trial_agent".to_string(),
@@ -83,6 +187,7 @@ fn init() {
                 address: "fetch1h6u0j6u0j6u0j6u0j6u0j6u0j6u0j6u1".to_string(),
                 capabilities: vec!["trial_analysis".to_string(), "matching_algorithm".to_string()],
                 status: "active".to_string(),
+                last_heartbeat: Utc::now().timestamp(),
             },
         ];
         
@@ -92,44 +197,526 @@ fn init() {
     }
 }
 
+// Fixed-size bucket store primitives. Cells are addressed by slot (records
+// start at slot 0, right after the reserved store-header prefix), each holding
+// a header (occupied, kind, uid, this chunk's payload len, continues) followed
+// by one chunk of a candid-encoded payload. `write_physical_cell`/
+// `read_physical_cell` and the store-header helpers are the only functions
+// that touch raw stable memory, so relocating or resizing the layout stays
+// contained here; `write_record`/`read_record` build on them to span a record
+// across as many chunks as it needs.
+fn fnv1a64(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+fn stable_offset_for_slot(slot: u64) -> u64 {
+    STORE_HEADER_RESERVED_BYTES + slot * CELL_SIZE_BYTES
+}
+
+fn ensure_stable_capacity(end_offset: u64) {
+    let required_pages = ((end_offset + WASM_PAGE_SIZE_BYTES - 1) / WASM_PAGE_SIZE_BYTES) as u32;
+    let current_pages = ic_cdk::api::stable::stable_size();
+    if required_pages > current_pages {
+        ic_cdk::api::stable::stable_grow(required_pages - current_pages)
+            .expect("failed to grow stable memory for the cell store");
+    }
+}
+
+fn write_physical_cell(slot: u64, kind: u8, uid: u64, chunk: &[u8], continues: bool) {
+    if slot >= MAX_CELLS {
+        ic_cdk::trap("stable storage cell capacity exceeded");
+    }
+    debug_assert!(chunk.len() <= CELL_PAYLOAD_BYTES);
+
+    let offset = stable_offset_for_slot(slot);
+    ensure_stable_capacity(offset + CELL_SIZE_BYTES);
+
+    let mut cell = vec![0u8; CELL_SIZE_BYTES as usize];
+    cell[0] = 1; // occupied
+    cell[1] = kind;
+    cell[2..10].copy_from_slice(&uid.to_le_bytes());
+    cell[10..14].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+    cell[14] = continues as u8;
+    cell[CELL_HEADER_BYTES as usize..CELL_HEADER_BYTES as usize + chunk.len()]
+        .copy_from_slice(chunk);
+
+    ic_cdk::api::stable::stable_write(offset as u32, &cell);
+}
+
+fn read_physical_cell(slot: u64) -> Option<(u8, u64, Vec<u8>, bool)> {
+    let offset = stable_offset_for_slot(slot);
+    let stable_bytes = ic_cdk::api::stable::stable_size() as u64 * WASM_PAGE_SIZE_BYTES;
+    if offset + CELL_SIZE_BYTES > stable_bytes {
+        return None;
+    }
+
+    let mut cell = vec![0u8; CELL_SIZE_BYTES as usize];
+    ic_cdk::api::stable::stable_read(offset as u32, &mut cell);
+    if cell[0] != 1 {
+        return None; // free cell
+    }
+
+    let kind = cell[1];
+    let uid = u64::from_le_bytes(cell[2..10].try_into().unwrap());
+    let len = u32::from_le_bytes(cell[10..14].try_into().unwrap()) as usize;
+    let continues = cell[14] != 0;
+    let chunk = cell[CELL_HEADER_BYTES as usize..CELL_HEADER_BYTES as usize + len].to_vec();
+    Some((kind, uid, chunk, continues))
+}
+
+// Writes `payload` starting at `start_slot`, splitting it across as many
+// consecutive cells as needed (one cell's worth of payload no longer caps
+// the size of a record). Returns the number of physical cells consumed.
+fn write_record(start_slot: u64, kind: u8, uid: u64, payload: &[u8]) -> u64 {
+    let chunks: Vec<&[u8]> = if payload.is_empty() {
+        vec![&[][..]]
+    } else {
+        payload.chunks(CELL_PAYLOAD_BYTES).collect()
+    };
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let continues = i + 1 < chunks.len();
+        write_physical_cell(start_slot + i as u64, kind, uid, chunk, continues);
+    }
+
+    chunks.len() as u64
+}
+
+// Reads the record chain starting at `start_slot`, reassembling chunks until
+// a cell reports `continues == false`. Returns the record plus the number of
+// physical cells it occupied, so the caller can advance past the whole chain.
+fn read_record(start_slot: u64) -> Option<(u8, u64, Vec<u8>, u64)> {
+    let (kind, uid, mut payload, mut continues) = read_physical_cell(start_slot)?;
+    let mut cells_used = 1u64;
+    while continues {
+        let (_, _, chunk, more) = read_physical_cell(start_slot + cells_used)?;
+        payload.extend_from_slice(&chunk);
+        continues = more;
+        cells_used += 1;
+    }
+    Some((kind, uid, payload, cells_used))
+}
+
+fn write_store_header(cell_count: u64) {
+    ensure_stable_capacity(STORE_HEADER_RESERVED_BYTES);
+    let mut header = vec![0u8; STORE_HEADER_RESERVED_BYTES as usize];
+    header[0..4].copy_from_slice(STORE_HEADER_MAGIC);
+    header[4..12].copy_from_slice(&cell_count.to_le_bytes());
+    ic_cdk::api::stable::stable_write(0, &header);
+}
+
+fn read_store_header() -> Option<u64> {
+    let stable_bytes = ic_cdk::api::stable::stable_size() as u64 * WASM_PAGE_SIZE_BYTES;
+    if stable_bytes < STORE_HEADER_RESERVED_BYTES {
+        return None;
+    }
+    let mut header = vec![0u8; STORE_HEADER_RESERVED_BYTES as usize];
+    ic_cdk::api::stable::stable_read(0, &mut header);
+    if &header[0..4] != STORE_HEADER_MAGIC {
+        return None;
+    }
+    Some(u64::from_le_bytes(header[4..12].try_into().unwrap()))
+}
+
+// Snapshots every live record into the bucket store, each record spilling
+// across as many cells as its encoding needs. Slots are packed from 0 on
+// every upgrade, so a record dropped from the heap since the last upgrade
+// simply has its old slots reused by whatever is written next — there is no
+// separate free-list to go stale.
+#[pre_upgrade]
+fn pre_upgrade() {
+    unsafe {
+        let mut slot = 0u64;
+
+        for (id, patient) in PATIENTS.as_ref().unwrap() {
+            let payload =
+                candid::encode_one(patient).expect("failed to encode patient for stable storage");
+            slot += write_record(slot, CELL_KIND_PATIENT, fnv1a64(id.as_bytes()), &payload);
+        }
+        for (id, trial) in TRIALS.as_ref().unwrap() {
+            let payload =
+                candid::encode_one(trial).expect("failed to encode trial for stable storage");
+            slot += write_record(slot, CELL_KIND_TRIAL, fnv1a64(id.as_bytes()), &payload);
+        }
+        for (id, record) in MATCHES.as_ref().unwrap() {
+            let payload =
+                candid::encode_one(record).expect("failed to encode match for stable storage");
+            slot += write_record(slot, CELL_KIND_MATCH, fnv1a64(id.as_bytes()), &payload);
+        }
+        for (id, agent) in FETCH_AGENTS.as_ref().unwrap() {
+            let payload =
+                candid::encode_one(agent).expect("failed to encode agent for stable storage");
+            slot += write_record(slot, CELL_KIND_AGENT, fnv1a64(id.as_bytes()), &payload);
+        }
+        for event in PROVENANCE.as_ref().unwrap() {
+            let payload = candid::encode_one(event)
+                .expect("failed to encode provenance event for stable storage");
+            slot += write_record(
+                slot,
+                CELL_KIND_PROVENANCE,
+                fnv1a64(event.id.as_bytes()),
+                &payload,
+            );
+        }
+        for (principal, role) in PRINCIPALS.as_ref().unwrap() {
+            let payload = candid::encode_one(&(principal.clone(), role.clone()))
+                .expect("failed to encode principal role for stable storage");
+            slot += write_record(
+                slot,
+                CELL_KIND_PRINCIPAL,
+                fnv1a64(principal.as_bytes()),
+                &payload,
+            );
+        }
+
+        let threshold_payload = candid::encode_one(&MATCH_SCORE_THRESHOLD)
+            .expect("failed to encode match-score threshold for stable storage");
+        slot += write_record(slot, CELL_KIND_MATCH_THRESHOLD, 0, &threshold_payload);
+
+        let heartbeat_window_payload = candid::encode_one(&(
+            HEARTBEAT_DEGRADED_AFTER_SECONDS,
+            HEARTBEAT_OFFLINE_AFTER_SECONDS,
+        ))
+        .expect("failed to encode heartbeat staleness window for stable storage");
+        slot += write_record(slot, CELL_KIND_HEARTBEAT_WINDOW, 0, &heartbeat_window_payload);
+
+        write_store_header(slot);
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let mut patients = HashMap::new();
+    let mut trials = HashMap::new();
+    let mut matches = HashMap::new();
+    let mut agents = HashMap::new();
+    let mut provenance = Vec::new();
+    let mut principals = HashMap::new();
+    let mut match_score_threshold = 0.5;
+    let mut heartbeat_degraded_after_seconds = 60i64;
+    let mut heartbeat_offline_after_seconds = 300i64;
+
+    if let Some(cell_count) = read_store_header() {
+        let mut slot = 0u64;
+        while slot < cell_count {
+            let Some((kind, _uid, payload, cells_used)) = read_record(slot) else {
+                break;
+            };
+            slot += cells_used;
+            match kind {
+                CELL_KIND_PATIENT => {
+                    let patient: Patient =
+                        candid::decode_one(&payload).expect("corrupt patient cell");
+                    patients.insert(patient.id.clone(), patient);
+                }
+                CELL_KIND_TRIAL => {
+                    let trial: ClinicalTrial =
+                        candid::decode_one(&payload).expect("corrupt trial cell");
+                    trials.insert(trial.id.clone(), trial);
+                }
+                CELL_KIND_MATCH => {
+                    let record: Match = candid::decode_one(&payload).expect("corrupt match cell");
+                    matches.insert(record.id.clone(), record);
+                }
+                CELL_KIND_AGENT => {
+                    let agent: FetchAgent =
+                        candid::decode_one(&payload).expect("corrupt agent cell");
+                    agents.insert(agent.id.clone(), agent);
+                }
+                CELL_KIND_PROVENANCE => {
+                    let event: ProvenanceEvent =
+                        candid::decode_one(&payload).expect("corrupt provenance cell");
+                    provenance.push(event);
+                }
+                CELL_KIND_PRINCIPAL => {
+                    let (principal, role): (String, Role) =
+                        candid::decode_one(&payload).expect("corrupt principal cell");
+                    principals.insert(principal, role);
+                }
+                CELL_KIND_MATCH_THRESHOLD => {
+                    match_score_threshold =
+                        candid::decode_one(&payload).expect("corrupt match-score threshold cell");
+                }
+                CELL_KIND_HEARTBEAT_WINDOW => {
+                    let (degraded_after, offline_after): (i64, i64) = candid::decode_one(&payload)
+                        .expect("corrupt heartbeat staleness window cell");
+                    heartbeat_degraded_after_seconds = degraded_after;
+                    heartbeat_offline_after_seconds = offline_after;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    unsafe {
+        PATIENTS = Some(patients);
+        TRIALS = Some(trials);
+        MATCHES = Some(matches);
+        FETCH_AGENTS = Some(agents);
+        PROVENANCE = Some(provenance);
+        PRINCIPALS = Some(principals);
+        MATCH_SCORE_THRESHOLD = match_score_threshold;
+        HEARTBEAT_DEGRADED_AFTER_SECONDS = heartbeat_degraded_after_seconds;
+        HEARTBEAT_OFFLINE_AFTER_SECONDS = heartbeat_offline_after_seconds;
+    }
+}
+
+#[query]
+pub fn get_storage_stats() -> StorageStats {
+    unsafe {
+        // Record count, not physical cell count: a record now spills across
+        // as many cells as its encoding needs, so this (and the resulting
+        // `bytes_used`) is a lower-bound estimate, not an exact cell tally.
+        // +2 for the reserved match-score-threshold and heartbeat-window
+        // records written every upgrade.
+        let cell_count = (PATIENTS.as_ref().unwrap().len()
+            + TRIALS.as_ref().unwrap().len()
+            + MATCHES.as_ref().unwrap().len()
+            + FETCH_AGENTS.as_ref().unwrap().len()
+            + PROVENANCE.as_ref().unwrap().len()
+            + PRINCIPALS.as_ref().unwrap().len()
+            + 2) as u64;
+
+        StorageStats {
+            cell_count,
+            capacity: MAX_CELLS,
+            bytes_used: cell_count * CELL_SIZE_BYTES,
+        }
+    }
+}
+
+// Access control: every principal is looked up in `PRINCIPALS` before it is
+// allowed to act; principals with no granted role are treated as anonymous.
+fn get_role(principal: &str) -> Option<Role> {
+    unsafe { PRINCIPALS.as_ref().unwrap().get(principal).cloned() }
+}
+
+fn is_admin(principal: &str) -> bool {
+    matches!(get_role(principal), Some(Role::Admin))
+}
+
+fn require_role(principal: &str, allowed: &[Role]) {
+    match get_role(principal) {
+        Some(role) if allowed.contains(&role) => {}
+        _ => ic_cdk::trap("caller is not authorized to perform this action"),
+    }
+}
+
+// Matching data is patient-specific, so only the owning patient or clinical
+// staff (Researcher/Admin) may trigger, read, or act on it.
+fn require_patient_or_clinical_role(patient_id: &str, caller_principal: &str) {
+    let is_owner = unsafe {
+        PATIENTS
+            .as_ref()
+            .unwrap()
+            .get(patient_id)
+            .map(|p| p.principal == caller_principal)
+            .unwrap_or(false)
+    };
+    let is_clinical = matches!(get_role(caller_principal), Some(Role::Researcher) | Some(Role::Admin));
+    if !is_owner && !is_clinical {
+        ic_cdk::trap("caller is not authorized to access this patient's matches");
+    }
+}
+
+#[update]
+pub fn grant_role(principal: String, role: Role) {
+    if !is_admin(&caller().to_string()) {
+        ic_cdk::trap("only an admin can grant roles");
+    }
+    unsafe {
+        PRINCIPALS.as_mut().unwrap().insert(principal, role);
+    }
+}
+
+#[update]
+pub fn revoke_role(principal: String) {
+    if !is_admin(&caller().to_string()) {
+        ic_cdk::trap("only an admin can revoke roles");
+    }
+    unsafe {
+        PRINCIPALS.as_mut().unwrap().remove(&principal);
+    }
+}
+
+// Provenance: append a tamper-evident audit event, chaining its hash onto
+// the previous event's hash so the log can be verified end-to-end.
+fn record_provenance(actor_principal: String, action: &str, entity_type: &str, entity_id: &str) {
+    unsafe {
+        let events = PROVENANCE.as_mut().unwrap();
+        let prev_hash = events.last().map(|e| e.hash.clone()).unwrap_or_default();
+        let id = Uuid::new_v4().to_string();
+        let timestamp = Utc::now().timestamp();
+
+        let mut hasher = Sha256::new();
+        hasher.update(id.as_bytes());
+        hasher.update(actor_principal.as_bytes());
+        hasher.update(action.as_bytes());
+        hasher.update(entity_type.as_bytes());
+        hasher.update(entity_id.as_bytes());
+        hasher.update(timestamp.to_le_bytes());
+        hasher.update(prev_hash.as_bytes());
+        let hash = format!("{:x}", hasher.finalize());
+
+        events.push(ProvenanceEvent {
+            id,
+            actor_principal,
+            action: action.to_string(),
+            entity_type: entity_type.to_string(),
+            entity_id: entity_id.to_string(),
+            timestamp,
+            prev_hash,
+            hash,
+        });
+    }
+}
+
+// Provenance can reveal which principal acted on an entity (e.g. who
+// registered a given patient id), so it is gated the same way patient reads
+// are: the entity's owning patient, or clinical staff (Researcher/Admin).
+#[query]
+pub fn get_provenance(entity_id: String) -> Vec<ProvenanceEvent> {
+    let caller_principal = caller().to_string();
+    unsafe {
+        let is_owning_patient = PATIENTS
+            .as_ref()
+            .unwrap()
+            .get(&entity_id)
+            .map(|p| p.principal == caller_principal)
+            .unwrap_or(false);
+
+        if !is_owning_patient
+            && !matches!(get_role(&caller_principal), Some(Role::Researcher) | Some(Role::Admin))
+        {
+            ic_cdk::trap("caller is not authorized to view provenance for this entity");
+        }
+
+        PROVENANCE
+            .as_ref()
+            .unwrap()
+            .iter()
+            .filter(|event| event.entity_id == entity_id)
+            .cloned()
+            .collect()
+    }
+}
+
+#[query]
+pub fn verify_provenance_chain() -> bool {
+    unsafe {
+        let mut expected_prev_hash = String::new();
+        for event in PROVENANCE.as_ref().unwrap().iter() {
+            if event.prev_hash != expected_prev_hash {
+                return false;
+            }
+
+            let mut hasher = Sha256::new();
+            hasher.update(event.id.as_bytes());
+            hasher.update(event.actor_principal.as_bytes());
+            hasher.update(event.action.as_bytes());
+            hasher.update(event.entity_type.as_bytes());
+            hasher.update(event.entity_id.as_bytes());
+            hasher.update(event.timestamp.to_le_bytes());
+            hasher.update(event.prev_hash.as_bytes());
+            let recomputed_hash = format!("{:x}", hasher.finalize());
+
+            if recomputed_hash != event.hash {
+                return false;
+            }
+            expected_prev_hash = event.hash.clone();
+        }
+        true
+    }
+}
+
 // Patient management
 #[update]
 pub fn register_patient(patient: Patient) -> String {
     let caller_principal = caller().to_string();
     let patient_id = Uuid::new_v4().to_string();
-    
+
     let mut new_patient = patient;
     new_patient.id = patient_id.clone();
-    new_patient.principal = caller_principal;
+    new_patient.principal = caller_principal.clone();
     new_patient.created_at = Utc::now().timestamp();
-    
+    new_patient.version = 1;
+
     unsafe {
         PATIENTS.as_mut().unwrap().insert(patient_id.clone(), new_patient);
     }
-    
+    record_provenance(caller_principal, "register_patient", "patient", &patient_id);
+
     patient_id
 }
 
 #[query]
 pub fn get_patient(id: String) -> Option<Patient> {
+    let caller_principal = caller().to_string();
+    unsafe {
+        let patient = PATIENTS.as_ref().unwrap().get(&id).cloned()?;
+        if patient.principal == caller_principal || is_admin(&caller_principal) {
+            Some(patient)
+        } else {
+            ic_cdk::trap("caller is not authorized to read this patient record");
+        }
+    }
+}
+
+// Compare-and-swap update: rejects the write with `Conflict` if `patient`'s
+// stored version has moved on since the caller last read it.
+#[update]
+pub fn update_patient(
+    id: String,
+    patient: Patient,
+    expected_version: u64,
+) -> Result<Patient, UpdateError> {
+    let caller_principal = caller().to_string();
     unsafe {
-        PATIENTS.as_ref().unwrap().get(&id).cloned()
+        let patients = PATIENTS.as_mut().unwrap();
+        let current = patients.get(&id).ok_or(UpdateError::NotFound)?;
+        if current.principal != caller_principal && !is_admin(&caller_principal) {
+            ic_cdk::trap("caller is not authorized to update this patient record");
+        }
+        if current.version != expected_version {
+            return Err(UpdateError::Conflict {
+                current_version: current.version,
+            });
+        }
+
+        let mut updated = patient;
+        updated.id = id.clone();
+        updated.principal = current.principal.clone();
+        updated.created_at = current.created_at;
+        updated.version = current.version + 1;
+
+        patients.insert(id.clone(), updated.clone());
+        Ok(updated)
     }
 }
 
 // Clinical trial management
 #[update]
 pub fn create_trial(trial: ClinicalTrial) -> String {
+    require_role(&caller().to_string(), &[Role::Researcher, Role::Admin]);
     let trial_id = Uuid::new_v4().to_string();
-    
+
     let mut new_trial = trial;
     new_trial.id = trial_id.clone();
     new_trial.created_at = Utc::now().timestamp();
-    
+    new_trial.version = 1;
+
     unsafe {
         TRIALS.as_mut().unwrap().insert(trial_id.clone(), new_trial);
     }
-    
+    record_provenance(caller().to_string(), "create_trial", "trial", &trial_id);
+
     trial_id
 }
 
@@ -140,18 +727,286 @@ pub fn get_all_trials() -> Vec<ClinicalTrial> {
     }
 }
 
+// Compare-and-swap update: rejects the write with `Conflict` if `trial`'s
+// stored version has moved on since the caller last read it.
+#[update]
+pub fn update_trial(
+    id: String,
+    trial: ClinicalTrial,
+    expected_version: u64,
+) -> Result<ClinicalTrial, UpdateError> {
+    require_role(&caller().to_string(), &[Role::Researcher, Role::Admin]);
+    unsafe {
+        let trials = TRIALS.as_mut().unwrap();
+        let current = trials.get(&id).ok_or(UpdateError::NotFound)?;
+        if current.version != expected_version {
+            return Err(UpdateError::Conflict {
+                current_version: current.version,
+            });
+        }
+
+        let mut updated = trial;
+        updated.id = id.clone();
+        updated.created_at = current.created_at;
+        updated.version = current.version + 1;
+
+        trials.insert(id.clone(), updated.clone());
+        Ok(updated)
+    }
+}
+
+// Patient<->trial matching
+fn jaccard_similarity(a: &[String], b: &[String]) -> f64 {
+    let set_a: HashSet<String> = a.iter().map(|s| s.to_lowercase()).collect();
+    let set_b: HashSet<String> = b.iter().map(|s| s.to_lowercase()).collect();
+    let union = set_a.union(&set_b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    set_a.intersection(&set_b).count() as f64 / union as f64
+}
+
+fn preference_overlap(preferences: &[String], requirements: &[String]) -> f64 {
+    if requirements.is_empty() {
+        return 1.0;
+    }
+    let preferences: HashSet<String> = preferences.iter().map(|s| s.to_lowercase()).collect();
+    let matched = requirements
+        .iter()
+        .filter(|r| preferences.contains(&r.to_lowercase()))
+        .count();
+    matched as f64 / requirements.len() as f64
+}
+
+fn compute_match_score(patient: &Patient, trial: &ClinicalTrial) -> f64 {
+    let condition_score = jaccard_similarity(&patient.conditions, &trial.conditions);
+    let location_score = if patient.location.eq_ignore_ascii_case(&trial.location) {
+        1.0
+    } else {
+        0.0
+    };
+    let preference_score = preference_overlap(&patient.preferences, &trial.requirements);
+
+    condition_score * CONDITION_OVERLAP_WEIGHT
+        + location_score * LOCATION_WEIGHT
+        + preference_score * PREFERENCE_WEIGHT
+}
+
+// Re-running this for a patient is expected as trials change, so an existing
+// `proposed` match for a (patient, trial) pair is refreshed in place rather
+// than duplicated; a match that already moved to `accepted`/`rejected` is
+// left untouched.
+#[update]
+pub fn compute_matches(patient_id: String) -> Vec<Match> {
+    let caller_principal = caller().to_string();
+    require_patient_or_clinical_role(&patient_id, &caller_principal);
+
+    unsafe {
+        let patient = match PATIENTS.as_ref().unwrap().get(&patient_id) {
+            Some(patient) => patient.clone(),
+            None => return Vec::new(),
+        };
+
+        let matches_store = MATCHES.as_mut().unwrap();
+        let mut results = Vec::new();
+
+        for trial in TRIALS.as_ref().unwrap().values() {
+            let score = compute_match_score(&patient, trial);
+            if score < MATCH_SCORE_THRESHOLD {
+                continue;
+            }
+
+            let existing_id = matches_store
+                .values()
+                .find(|m| m.patient_id == patient_id && m.trial_id == trial.id)
+                .map(|m| m.id.clone());
+
+            let result = match existing_id {
+                Some(id) if matches_store.get(&id).unwrap().status != MATCH_STATUS_PROPOSED => {
+                    matches_store.get(&id).unwrap().clone()
+                }
+                Some(id) => {
+                    let existing = matches_store.get(&id).unwrap();
+                    let mut refreshed = existing.clone();
+                    refreshed.match_score = score;
+                    refreshed.version += 1;
+                    matches_store.insert(id.clone(), refreshed.clone());
+                    record_provenance(caller_principal.clone(), "compute_matches", "match", &id);
+                    refreshed
+                }
+                None => {
+                    let new_match = Match {
+                        id: Uuid::new_v4().to_string(),
+                        patient_id: patient_id.clone(),
+                        trial_id: trial.id.clone(),
+                        match_score: score,
+                        status: MATCH_STATUS_PROPOSED.to_string(),
+                        created_at: Utc::now().timestamp(),
+                        version: 1,
+                    };
+                    matches_store.insert(new_match.id.clone(), new_match.clone());
+                    record_provenance(
+                        caller_principal.clone(),
+                        "compute_matches",
+                        "match",
+                        &new_match.id,
+                    );
+                    new_match
+                }
+            };
+
+            results.push(result);
+        }
+
+        results
+    }
+}
+
+#[query]
+pub fn get_matches_for_patient(patient_id: String) -> Vec<Match> {
+    require_patient_or_clinical_role(&patient_id, &caller().to_string());
+    unsafe {
+        MATCHES
+            .as_ref()
+            .unwrap()
+            .values()
+            .filter(|m| m.patient_id == patient_id)
+            .cloned()
+            .collect()
+    }
+}
+
+// Compare-and-swap transition: `proposed` -> `accepted` / `rejected` only, per
+// `is_valid_match_transition` — any other status value or starting state is
+// rejected rather than stored verbatim.
+#[update]
+pub fn update_match_status(
+    match_id: String,
+    status: String,
+    expected_version: u64,
+) -> Result<Match, UpdateError> {
+    let caller_principal = caller().to_string();
+    unsafe {
+        let matches_store = MATCHES.as_mut().unwrap();
+        let current = matches_store.get(&match_id).ok_or(UpdateError::NotFound)?;
+        require_patient_or_clinical_role(&current.patient_id, &caller_principal);
+        if current.version != expected_version {
+            return Err(UpdateError::Conflict {
+                current_version: current.version,
+            });
+        }
+        if !is_valid_match_transition(&current.status, &status) {
+            return Err(UpdateError::InvalidTransition {
+                current_status: current.status.clone(),
+            });
+        }
+
+        let mut updated = current.clone();
+        updated.status = status;
+        updated.version = current.version + 1;
+        matches_store.insert(match_id.clone(), updated.clone());
+
+        record_provenance(caller_principal, "update_match_status", "match", &match_id);
+        Ok(updated)
+    }
+}
+
+#[update]
+pub fn set_match_score_threshold(threshold: f64) {
+    if !is_admin(&caller().to_string()) {
+        ic_cdk::trap("only an admin can change the match-score threshold");
+    }
+    unsafe {
+        MATCH_SCORE_THRESHOLD = threshold;
+    }
+}
+
 // Fetch.ai agent integration
+fn effective_agent_status(agent: &FetchAgent) -> String {
+    let staleness = Utc::now().timestamp() - agent.last_heartbeat;
+    unsafe {
+        if staleness > HEARTBEAT_OFFLINE_AFTER_SECONDS {
+            "offline".to_string()
+        } else if staleness > HEARTBEAT_DEGRADED_AFTER_SECONDS {
+            "degraded".to_string()
+        } else {
+            "active".to_string()
+        }
+    }
+}
+
+#[update]
+pub fn set_heartbeat_staleness_window(degraded_after_seconds: i64, offline_after_seconds: i64) {
+    if !is_admin(&caller().to_string()) {
+        ic_cdk::trap("only an admin can change the heartbeat staleness window");
+    }
+    unsafe {
+        HEARTBEAT_DEGRADED_AFTER_SECONDS = degraded_after_seconds;
+        HEARTBEAT_OFFLINE_AFTER_SECONDS = offline_after_seconds;
+    }
+}
+
 #[query]
 pub fn get_fetch_agents() -> Vec<FetchAgent> {
     unsafe {
-        FETCH_AGENTS.as_ref().unwrap().values().cloned().collect()
+        FETCH_AGENTS
+            .as_ref()
+            .unwrap()
+            .values()
+            .cloned()
+            .map(|mut agent| {
+                agent.status = effective_agent_status(&agent);
+                agent
+            })
+            .collect()
     }
 }
 
+// Restricted to Researcher/Admin (the same bar as `trigger_agent_analysis`)
+// so an unauthenticated caller can't keep a dead agent reporting "active" by
+// pinging its id, which would otherwise defeat the whole health check.
+#[update]
+pub fn agent_heartbeat(agent_id: String) -> bool {
+    require_role(&caller().to_string(), &[Role::Researcher, Role::Admin]);
+    unsafe {
+        match FETCH_AGENTS.as_mut().unwrap().get_mut(&agent_id) {
+            Some(agent) => {
+                agent.last_heartbeat = Utc::now().timestamp();
+                agent.status = "active".to_string();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[query]
+pub fn get_healthy_agents(capability: String) -> Vec<FetchAgent> {
+    get_fetch_agents()
+        .into_iter()
+        .filter(|agent| agent.status == "active" && agent.capabilities.contains(&capability))
+        .collect()
+}
+
 #[update]
 pub fn trigger_agent_analysis(patient_id: String) -> String {
+    require_role(&caller().to_string(), &[Role::Researcher, Role::Admin]);
+
+    if get_healthy_agents("patient_analysis".to_string()).is_empty() {
+        return format!(
+            "No healthy Fetch.ai agents available to analyze patient {}",
+            patient_id
+        );
+    }
+
     // This would integrate with Fetch.ai agents
     // For now, return a mock analysis result
+    record_provenance(
+        caller().to_string(),
+        "trigger_agent_analysis",
+        "patient",
+        &patient_id,
+    );
     format!("Analysis triggered for patient {} via Fetch.ai agents", patient_id)
 }
 